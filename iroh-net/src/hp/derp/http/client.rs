@@ -0,0 +1,411 @@
+//! A client connection to a single derp server: dials the server over
+//! TCP/TLS or, for relays co-located on the same host, a Unix domain
+//! socket, and speaks the derp protocol over whichever transport it
+//! connected with.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    sync::Mutex,
+};
+
+use crate::hp::{
+    derp::{MeshKey, PacketForwarderHandler, ReceivedMessage},
+    key::node::{PublicKey, SecretKey},
+};
+
+use super::mesh_clients::{LinkCounters, LinkHandle, LinkStateAdvertisement, MeshTarget, RoutingState};
+
+/// Tags discriminating the kinds of frame carried over a mesh link. A plain
+/// client connection (see [`Client::send`]/[`Client::recv_detail`]) never
+/// sees these; they are only ever written or read by
+/// [`Client::run_mesh_client`].
+const FRAME_HELLO: u8 = 0;
+const FRAME_ADVERTISEMENT: u8 = 1;
+const FRAME_MESH_PACKET: u8 = 2;
+
+/// How often a mesh link floods this server's link-state advertisement to
+/// its neighbor.
+const LSA_FLOOD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where a [`Client`] dials to reach its server.
+#[derive(Debug, Clone)]
+enum Target {
+    /// An http(s) derp server, reached over TCP/TLS.
+    Url(Url),
+    /// A derp server listening on a Unix domain socket at this path, for a
+    /// relay co-located on the same host.
+    Socket(PathBuf),
+}
+
+/// Builds a [`Client`] for a single derp server target.
+#[derive(Debug, Default)]
+pub(crate) struct ClientBuilder {
+    mesh_key: Option<MeshKey>,
+    target: Option<Target>,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the mesh key kept on the built client. The key is not sent to
+    /// the remote server in any frame this client writes.
+    pub(crate) fn mesh_key(mut self, mesh_key: Option<MeshKey>) -> Self {
+        self.mesh_key = mesh_key;
+        self
+    }
+
+    /// Dials the derp server at `url` over TCP/TLS.
+    pub(crate) fn server_url(mut self, url: Url) -> Self {
+        self.target = Some(Target::Url(url));
+        self
+    }
+
+    /// Dials the derp server listening on a Unix domain socket at `path`,
+    /// skipping TCP/TLS entirely for a relay co-located on the same host.
+    pub(crate) fn server_socket(mut self, path: PathBuf) -> Self {
+        self.target = Some(Target::Socket(path));
+        self
+    }
+
+    pub(crate) fn build(self, secret_key: SecretKey) -> Result<Client> {
+        let target = self
+            .target
+            .context("ClientBuilder needs a server_url or server_socket target")?;
+        Ok(Client {
+            target,
+            _mesh_key: self.mesh_key,
+            _secret_key: secret_key,
+            conn: Mutex::new(None),
+        })
+    }
+}
+
+/// The transport underlying an open [`Client`] connection.
+#[derive(Debug)]
+enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    async fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        let len = u32::try_from(payload.len()).context("frame too large to send")?;
+        let result = match self {
+            Conn::Tcp(stream) => {
+                stream.write_all(&len.to_be_bytes()).await?;
+                stream.write_all(payload).await
+            }
+            Conn::Unix(stream) => {
+                stream.write_all(&len.to_be_bytes()).await?;
+                stream.write_all(payload).await
+            }
+        };
+        result.context("failed to write frame to mesh peer")
+    }
+
+    async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let mut len_buf = [0u8; 4];
+        match self {
+            Conn::Tcp(stream) => stream.read_exact(&mut len_buf).await?,
+            Conn::Unix(stream) => stream.read_exact(&mut len_buf).await?,
+        };
+        let mut buf = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+        match self {
+            Conn::Tcp(stream) => stream.read_exact(&mut buf).await?,
+            Conn::Unix(stream) => stream.read_exact(&mut buf).await?,
+        };
+        Ok(buf)
+    }
+}
+
+/// A connection to a single derp server, used both as a plain client and,
+/// via [`Client::run_mesh_client`], as a mesh peering link.
+pub(crate) struct Client {
+    target: Target,
+    _mesh_key: Option<MeshKey>,
+    _secret_key: SecretKey,
+    conn: Mutex<Option<Conn>>,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("target", &self.target)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    async fn dial(&self) -> Result<Conn> {
+        match &self.target {
+            Target::Url(url) => {
+                let host = url.host_str().context("mesh server url has no host")?;
+                let port = url
+                    .port_or_known_default()
+                    .context("mesh server url has no resolvable port")?;
+                let stream = TcpStream::connect((host, port))
+                    .await
+                    .with_context(|| format!("failed to connect to {url}"))?;
+                Ok(Conn::Tcp(stream))
+            }
+            Target::Socket(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("failed to connect to {}", path.display()))?;
+                Ok(Conn::Unix(stream))
+            }
+        }
+    }
+
+    /// Connects to the configured target if a connection is not already
+    /// open.
+    pub(crate) async fn connect(&self) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(self.dial().await?);
+        }
+        Ok(())
+    }
+
+    /// Sends `data` to `dst` over this connection.
+    pub(crate) async fn send(&self, dst: PublicKey, data: Bytes) -> Result<()> {
+        self.connect().await?;
+        let mut conn = self.conn.lock().await;
+        let conn = conn.as_mut().expect("connected above");
+        let mut frame = Vec::with_capacity(32 + data.len());
+        frame.extend_from_slice(dst.as_bytes());
+        frame.extend_from_slice(&data);
+        conn.write_frame(&frame).await
+    }
+
+    /// Reads the next packet from this connection, along with the number of
+    /// bytes the frame it arrived in carried.
+    pub(crate) async fn recv_detail(&self) -> Result<(ReceivedMessage, usize)> {
+        self.connect().await?;
+        let mut conn = self.conn.lock().await;
+        let conn = conn.as_mut().expect("connected above");
+        let frame = conn.read_frame().await?;
+        if frame.len() < 32 {
+            bail!("short frame from mesh peer");
+        }
+        let (source, data) = frame.split_at(32);
+        let source = PublicKey::try_from(source).context("invalid source node key in frame")?;
+        let len = frame.len();
+        Ok((
+            ReceivedMessage::ReceivedPacket {
+                source,
+                data: Bytes::copy_from_slice(data),
+            },
+            len,
+        ))
+    }
+
+    async fn send_frame(&self, tag: u8, payload: &[u8]) -> Result<()> {
+        self.connect().await?;
+        let mut conn = self.conn.lock().await;
+        let conn = conn.as_mut().expect("connected above");
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(tag);
+        frame.extend_from_slice(payload);
+        conn.write_frame(&frame).await
+    }
+
+    async fn recv_frame(&self) -> Result<(u8, Vec<u8>)> {
+        self.connect().await?;
+        let mut conn = self.conn.lock().await;
+        let conn = conn.as_mut().expect("connected above");
+        let frame = conn.read_frame().await?;
+        let (tag, payload) = frame.split_first().context("empty frame from mesh peer")?;
+        Ok((*tag, payload.to_vec()))
+    }
+
+    /// Exchanges node keys with the remote end of a mesh link: sends
+    /// `local_key` and returns the key the remote side sent back.
+    async fn hello(&self, local_key: PublicKey) -> Result<PublicKey> {
+        self.send_frame(FRAME_HELLO, local_key.as_bytes()).await?;
+        let (tag, payload) = self.recv_frame().await?;
+        if tag != FRAME_HELLO {
+            bail!("expected a hello frame from mesh peer, got frame tag {tag}");
+        }
+        PublicKey::try_from(payload.as_slice()).context("invalid node key in hello frame")
+    }
+
+    /// Sends this server's link-state advertisement to the remote end of a
+    /// mesh link.
+    async fn send_advertisement(&self, advertisement: &LinkStateAdvertisement) -> Result<()> {
+        let mut payload = Vec::with_capacity(40 + advertisement.neighbors.len() * 32);
+        payload.extend_from_slice(advertisement.origin.as_bytes());
+        payload.extend_from_slice(&advertisement.seq.to_be_bytes());
+        payload.extend_from_slice(&(advertisement.neighbors.len() as u32).to_be_bytes());
+        for neighbor in &advertisement.neighbors {
+            payload.extend_from_slice(neighbor.as_bytes());
+        }
+        self.send_frame(FRAME_ADVERTISEMENT, &payload).await
+    }
+
+    /// Forwards `data` to `dest` over a mesh link, tagged with its original
+    /// `source` and the number of hops it has already traveled.
+    async fn send_mesh_packet(
+        &self,
+        source: PublicKey,
+        dest: PublicKey,
+        hops: u8,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut payload = Vec::with_capacity(65 + data.len());
+        payload.extend_from_slice(source.as_bytes());
+        payload.extend_from_slice(dest.as_bytes());
+        payload.push(hops);
+        payload.extend_from_slice(data);
+        self.send_frame(FRAME_MESH_PACKET, &payload).await
+    }
+
+    /// Reads the next advertisement or packet frame from the remote end of a
+    /// mesh link, along with the number of bytes it carried.
+    async fn recv_mesh_frame(&self) -> Result<(MeshFrame, usize)> {
+        let (tag, payload) = self.recv_frame().await?;
+        let len = payload.len();
+        match tag {
+            FRAME_ADVERTISEMENT => {
+                if payload.len() < 44 {
+                    bail!("short advertisement frame from mesh peer");
+                }
+                let origin = PublicKey::try_from(&payload[0..32])
+                    .context("invalid origin node key in advertisement frame")?;
+                let seq = u64::from_be_bytes(payload[32..40].try_into().unwrap());
+                let count = u32::from_be_bytes(payload[40..44].try_into().unwrap()) as usize;
+                if payload.len() != 44 + count * 32 {
+                    bail!("advertisement frame neighbor count does not match its length");
+                }
+                let mut neighbors = Vec::with_capacity(count);
+                let mut offset = 44;
+                for _ in 0..count {
+                    let key = PublicKey::try_from(&payload[offset..offset + 32])
+                        .context("invalid neighbor node key in advertisement frame")?;
+                    neighbors.push(key);
+                    offset += 32;
+                }
+                Ok((
+                    MeshFrame::Advertisement(LinkStateAdvertisement {
+                        origin,
+                        neighbors,
+                        seq,
+                    }),
+                    len,
+                ))
+            }
+            FRAME_MESH_PACKET => {
+                if payload.len() < 65 {
+                    bail!("short packet frame from mesh peer");
+                }
+                let source = PublicKey::try_from(&payload[0..32])
+                    .context("invalid source node key in packet frame")?;
+                let dest = PublicKey::try_from(&payload[32..64])
+                    .context("invalid dest node key in packet frame")?;
+                let hops = payload[64];
+                let data = Bytes::copy_from_slice(&payload[65..]);
+                Ok((
+                    MeshFrame::Packet {
+                        source,
+                        dest,
+                        hops,
+                        data,
+                    },
+                    len,
+                ))
+            }
+            other => bail!("unexpected frame tag {other} on mesh link"),
+        }
+    }
+
+    /// Runs this connection as a mesh peering link: exchanges node keys with
+    /// the remote server, then floods this server's link-state
+    /// advertisement every [`LSA_FLOOD_INTERVAL`] while relaying every
+    /// received packet either to `handler` (if addressed to one of this
+    /// server's local clients) or, failing that, onward to the neighbor
+    /// [`RoutingState::next_hop`] picks from `links`. Runs until the
+    /// connection errors or is dropped.
+    pub(crate) async fn run_mesh_client(
+        &self,
+        addr: MeshTarget,
+        handler: PacketForwarderHandler<Client>,
+        counters: Arc<LinkCounters>,
+        routing: Arc<StdMutex<RoutingState>>,
+        links: Arc<StdMutex<HashMap<MeshTarget, LinkHandle>>>,
+        local_key: PublicKey,
+    ) -> Result<()> {
+        let remote_key = self.hello(local_key).await?;
+        routing
+            .lock()
+            .unwrap()
+            .record_neighbor_key(addr.clone(), remote_key);
+
+        let mut flood = tokio::time::interval(LSA_FLOOD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = flood.tick() => {
+                    let advertisement = routing.lock().unwrap().local_advertisement(local_key);
+                    self.send_advertisement(&advertisement).await?;
+                }
+                frame = self.recv_mesh_frame() => {
+                    let (frame, len) = frame?;
+                    counters.record_received(len as u64);
+                    match frame {
+                        MeshFrame::Advertisement(advertisement) => {
+                            routing
+                                .lock()
+                                .unwrap()
+                                .receive_advertisement(local_key, advertisement);
+                        }
+                        MeshFrame::Packet { source, dest, hops, data } => {
+                            if handler.forward_packet(source, dest, data.clone()).await {
+                                continue;
+                            }
+                            let next = routing.lock().unwrap().next_hop(&dest, hops);
+                            let Some(next_addr) = next else { continue };
+                            let next_link = links.lock().unwrap().get(&next_addr).cloned();
+                            let Some(next_link) = next_link else { continue };
+                            if let Err(e) = next_link
+                                .client
+                                .send_mesh_packet(source, dest, hops + 1, &data)
+                                .await
+                            {
+                                tracing::warn!(
+                                    "failed to forward packet to next hop {next_addr:?}: {e:?}"
+                                );
+                                continue;
+                            }
+                            next_link.counters.record_sent(len as u64);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A decoded frame received over a mesh link.
+enum MeshFrame {
+    /// A link-state advertisement flooded by the neighbor.
+    Advertisement(LinkStateAdvertisement),
+    /// A packet the neighbor is relaying, possibly for further forwarding.
+    Packet {
+        source: PublicKey,
+        dest: PublicKey,
+        hops: u8,
+        data: Bytes,
+    },
+}
@@ -1,14 +1,201 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
 use reqwest::Url;
-use tokio::task::JoinSet;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
 use crate::hp::{
     derp::{http::ClientBuilder, DerpMap, MeshKey, PacketForwarderHandler},
-    key::node::SecretKey,
+    key::node::{PublicKey, SecretKey},
 };
 
 use super::Client;
 
+/// How many hops a multi-hop forwarded packet may traverse before being
+/// dropped, to prevent routing loops across a partial mesh.
+pub(crate) const MAX_FORWARD_HOPS: u8 = 8;
+
+/// Initial delay before the first reconnect attempt after a disconnect.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound the backoff doubles towards.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long a link has to stay up before a subsequent disconnect resets the
+/// backoff back to `INITIAL_BACKOFF`, rather than continuing to grow it.
+const HEALTHY_THRESHOLD: Duration = Duration::from_secs(10);
+/// Fraction of a Basalt view's bucket seeds rotated on each resample, to
+/// force churn in the view over time.
+const BASALT_ROTATE_FRACTION: f64 = 0.1;
+
+/// Doubles `backoff` for the next reconnect attempt, capped at `MAX_BACKOFF`.
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+/// The health of a single mesh link, as observed by its supervisor loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkHealth {
+    /// Attempting to (re)connect to the remote server.
+    Connecting,
+    /// Connected and running normally.
+    Connected,
+    /// The last attempt ended in an error or disconnect; waiting out a
+    /// backoff before retrying.
+    Backoff,
+}
+
+/// Byte and packet counters for traffic forwarded to, and received from, a
+/// single meshed server. Updated from the supervisor task on every send and
+/// receive; read back through [`MeshClients::stats`] for a snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct LinkCounters {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_received: AtomicU64,
+}
+
+impl LinkCounters {
+    pub(crate) fn record_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A point-in-time snapshot of the traffic counters for a single mesh link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeshLinkStats {
+    /// The target this link connects to.
+    pub target: MeshTarget,
+    /// Bytes forwarded to the remote server.
+    pub bytes_sent: u64,
+    /// Packets forwarded to the remote server.
+    pub packets_sent: u64,
+    /// Bytes received from the remote server.
+    pub bytes_received: u64,
+    /// Packets received from the remote server.
+    pub packets_received: u64,
+}
+
+/// A live mesh link's connection handle and traffic counters, registered so
+/// a packet arriving on one link's supervisor task can be forwarded out
+/// over another link chosen as a multi-hop next hop.
+#[derive(Clone)]
+pub(crate) struct LinkHandle {
+    pub(crate) client: Arc<Client>,
+    pub(crate) counters: Arc<LinkCounters>,
+}
+
+impl std::fmt::Debug for LinkHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LinkHandle").finish_non_exhaustive()
+    }
+}
+
+/// Shared routing state for multi-hop forwarding: this server's directly
+/// meshed neighbor keys, the resulting forwarding table, and this server's
+/// own link-state sequence number. Held behind a mutex so every mesh link's
+/// supervisor task can flood advertisements and look up next hops
+/// concurrently.
+#[derive(Debug, Default)]
+pub(crate) struct RoutingState {
+    neighbor_keys: HashMap<MeshTarget, PublicKey>,
+    forwarding: ForwardingTable,
+    lsa_seq: u64,
+}
+
+impl RoutingState {
+    /// Records the node key of the neighboring server reached through
+    /// `addr`, once it is learned (from the mesh link's handshake). Used to
+    /// build this server's own link-state advertisement.
+    pub(crate) fn record_neighbor_key(&mut self, addr: MeshTarget, key: PublicKey) {
+        self.neighbor_keys.insert(addr, key);
+    }
+
+    /// Builds this server's current link-state advertisement, to be flooded
+    /// across every directly meshed link. Bumps the sequence number, so
+    /// receivers can tell it apart from (and discard anything older than)
+    /// the last one we sent.
+    pub(crate) fn local_advertisement(&mut self, local_key: PublicKey) -> LinkStateAdvertisement {
+        self.lsa_seq += 1;
+        LinkStateAdvertisement {
+            origin: local_key,
+            neighbors: self.neighbor_keys.values().copied().collect(),
+            seq: self.lsa_seq,
+        }
+    }
+
+    /// Ingests a link-state advertisement received over a mesh link and
+    /// recomputes the forwarding table. Stale advertisements (same or older
+    /// sequence number than the last one seen from that origin) are
+    /// discarded, which also suppresses re-flooded duplicates.
+    pub(crate) fn receive_advertisement(
+        &mut self,
+        local_key: PublicKey,
+        advertisement: LinkStateAdvertisement,
+    ) {
+        self.forwarding.ingest(advertisement);
+        self.recompute_forwarding(local_key);
+    }
+
+    /// Forgets the neighbor reached through `addr`, once its mesh link is
+    /// torn down (by `reconcile` dropping the target, or by the supervisor's
+    /// own reconnect loop observing a disconnect), and recomputes the
+    /// forwarding table. Otherwise a route discovered while the link was up
+    /// would keep pointing at it forever, black-holing packets instead of
+    /// routing around the failure.
+    pub(crate) fn forget_neighbor(&mut self, local_key: PublicKey, addr: &MeshTarget) {
+        if let Some(key) = self.neighbor_keys.remove(addr) {
+            self.forwarding.forget(&key);
+        }
+        self.recompute_forwarding(local_key);
+    }
+
+    fn recompute_forwarding(&mut self, local_key: PublicKey) {
+        let neighbors_by_key = self
+            .neighbor_keys
+            .iter()
+            .map(|(addr, key)| (*key, addr.clone()))
+            .collect();
+        self.forwarding.recompute(local_key, &neighbors_by_key);
+    }
+
+    /// The neighbor target a packet addressed to `dest` should be forwarded
+    /// to, if a route is currently known and `hops` has not yet reached
+    /// [`MAX_FORWARD_HOPS`].
+    pub(crate) fn next_hop(&self, dest: &PublicKey, hops: u8) -> Option<MeshTarget> {
+        if hops >= MAX_FORWARD_HOPS {
+            return None;
+        }
+        self.forwarding.next_hop(dest).cloned()
+    }
+}
+
+/// A running mesh client task, along with the `CancellationToken` that
+/// controls its lifetime independently of its siblings and the health state
+/// its supervisor loop reports.
+#[derive(Debug)]
+struct MeshClientTask {
+    cancel: CancellationToken,
+    handle: JoinHandle<()>,
+    health: Arc<Mutex<LinkHealth>>,
+    counters: Arc<LinkCounters>,
+}
+
 /// Spawns, connects, and manages special `derp::http::Clients`.
 ///
 /// These clients handled incoming network update notifications from remote
@@ -17,10 +204,21 @@ use super::Client;
 /// A `mesh_key` is used to ensure the remote server belongs to the same mesh network.
 #[derive(Debug)]
 pub(crate) struct MeshClients {
-    tasks: JoinSet<()>,
+    tasks: HashMap<MeshTarget, MeshClientTask>,
     mesh_key: MeshKey,
     server_key: SecretKey,
     mesh_addrs: MeshAddrs,
+    /// The Basalt bounded random view backing `MeshAddrs::Sampled`, if that
+    /// is the variant currently configured.
+    basalt: Option<BasaltView>,
+    /// Shared routing state (neighbor keys, forwarding table, LSA sequence
+    /// number), behind a mutex so every mesh link's supervisor task can
+    /// flood advertisements and look up next hops concurrently.
+    routing: Arc<Mutex<RoutingState>>,
+    /// Connection handle and traffic counters for every currently connected
+    /// mesh link, keyed by target, so a packet arriving on one link can be
+    /// forwarded out over another as a multi-hop next hop.
+    links: Arc<Mutex<HashMap<MeshTarget, LinkHandle>>>,
     packet_fwd: PacketForwarderHandler<Client>,
     cancel: CancellationToken,
 }
@@ -33,17 +231,339 @@ impl MeshClients {
         packet_fwd: PacketForwarderHandler<Client>,
     ) -> Self {
         Self {
-            tasks: JoinSet::new(),
+            tasks: HashMap::new(),
             cancel: CancellationToken::new(),
             mesh_key,
             server_key,
             mesh_addrs,
+            basalt: None,
+            routing: Arc::new(Mutex::new(RoutingState::default())),
+            links: Arc::new(Mutex::new(HashMap::new())),
             packet_fwd,
         }
     }
 
+    /// Connects to every target `mesh_addrs` currently resolves to; for
+    /// `MeshAddrs::Sampled` that is only the targets in its Basalt view.
     pub(crate) async fn mesh(&mut self) {
-        let addrs = match &self.mesh_addrs {
+        let new_addrs: HashSet<MeshTarget> = self.resolve_addrs().into_iter().collect();
+        self.reconcile(new_addrs);
+    }
+
+    /// Reconciles the set of running mesh clients against a new `MeshAddrs`.
+    ///
+    /// Spawns clients for targets that are newly present, and cancels the
+    /// task of any client whose target is no longer part of the mesh.
+    /// Targets present in both the old and the new `MeshAddrs` are left
+    /// untouched, so healthy links are not dropped just because the
+    /// membership list was refreshed.
+    pub(crate) async fn update_mesh_addrs(&mut self, mesh_addrs: MeshAddrs) {
+        if !matches!(mesh_addrs, MeshAddrs::Sampled { .. }) {
+            self.basalt = None;
+        }
+        self.mesh_addrs = mesh_addrs;
+        let new_addrs: HashSet<MeshTarget> = self.resolve_addrs().into_iter().collect();
+        self.reconcile(new_addrs);
+    }
+
+    /// Rotates a fraction of the Basalt view's bucket seeds and reconciles
+    /// the running mesh clients against the recomputed view. A no-op unless
+    /// `mesh_addrs` is `MeshAddrs::Sampled`.
+    pub(crate) async fn resample(&mut self) {
+        if !matches!(self.mesh_addrs, MeshAddrs::Sampled { .. }) {
+            return;
+        }
+        if let Some(view) = &mut self.basalt {
+            view.rotate_seeds(BASALT_ROTATE_FRACTION);
+        }
+        let new_addrs: HashSet<MeshTarget> = self.resolve_addrs().into_iter().collect();
+        self.reconcile(new_addrs);
+    }
+
+    /// Resolves `mesh_addrs` into the concrete set of targets to connect to,
+    /// computing (or reusing) the Basalt view for `MeshAddrs::Sampled`.
+    fn resolve_addrs(&mut self) -> Vec<MeshTarget> {
+        match &self.mesh_addrs {
+            MeshAddrs::Sampled {
+                candidates,
+                view_size,
+            } => {
+                let candidates = candidates.clone();
+                let view_size = *view_size;
+                let view = self
+                    .basalt
+                    .get_or_insert_with(|| BasaltView::new(view_size));
+                if view.len() != view_size {
+                    *view = BasaltView::new(view_size);
+                }
+                view.recompute(&candidates);
+                view.view().into_iter().map(MeshTarget::Url).collect()
+            }
+            MeshAddrs::Targets(targets) => targets.clone(),
+            other => other.addrs().into_iter().map(MeshTarget::Url).collect(),
+        }
+    }
+
+    /// Spawns clients for targets newly present in `new_addrs` and cancels
+    /// the task of any running client whose target is no longer present.
+    fn reconcile(&mut self, new_addrs: HashSet<MeshTarget>) {
+        let current_addrs: HashSet<MeshTarget> = self.tasks.keys().cloned().collect();
+
+        for addr in current_addrs.difference(&new_addrs) {
+            if let Some(task) = self.tasks.remove(addr) {
+                task.cancel.cancel();
+                task.handle.abort();
+            }
+            self.links.lock().unwrap().remove(addr);
+            self.forget_neighbor(addr);
+        }
+
+        for addr in new_addrs.difference(&current_addrs) {
+            self.spawn_client(addr.clone());
+        }
+    }
+
+    /// Builds a client for `addr` and spawns its supervisor task, tracking it
+    /// under its own `CancellationToken` keyed by `addr`.
+    ///
+    /// The supervisor retries `run_mesh_client` with exponential backoff
+    /// whenever the connection errors or drops, so a transient network blip
+    /// does not permanently remove a packet forwarder.
+    fn spawn_client(&mut self, addr: MeshTarget) {
+        let mesh_key = self.mesh_key;
+        let server_key = self.server_key.clone();
+        let local_key = self.server_key.public_key();
+        let packet_forwarder_handler = self.packet_fwd.clone();
+        let health = Arc::new(Mutex::new(LinkHealth::Connecting));
+        let task_health = health.clone();
+        let counters = Arc::new(LinkCounters::default());
+        let task_counters = counters.clone();
+        let routing = self.routing.clone();
+        let links = self.links.clone();
+        let cancel = CancellationToken::new();
+        let task_cancel = cancel.clone();
+
+        let handle = tokio::task::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                *task_health.lock().unwrap() = LinkHealth::Connecting;
+                let builder = ClientBuilder::new().mesh_key(Some(mesh_key));
+                let builder = match &addr {
+                    MeshTarget::Url(url) => builder.server_url(url.clone()),
+                    MeshTarget::Socket(path) => builder.server_socket(path.clone()),
+                };
+                let client = match builder.build(server_key.clone()) {
+                    Ok(client) => Arc::new(client),
+                    Err(e) => {
+                        tracing::warn!("failed to build mesh client for {addr:?}: {e:?}");
+                        *task_health.lock().unwrap() = LinkHealth::Backoff;
+                        tokio::select! {
+                            _ = task_cancel.cancelled() => return,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = next_backoff(backoff);
+                        continue;
+                    }
+                };
+
+                let connected = tokio::select! {
+                    _ = task_cancel.cancelled() => return,
+                    res = client.connect() => res,
+                };
+                if let Err(e) = connected {
+                    tracing::warn!("failed to connect mesh client for {addr:?}: {e:?}");
+                    *task_health.lock().unwrap() = LinkHealth::Backoff;
+                    tokio::select! {
+                        _ = task_cancel.cancelled() => return,
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    backoff = next_backoff(backoff);
+                    continue;
+                }
+
+                *task_health.lock().unwrap() = LinkHealth::Connected;
+                let connected_at = Instant::now();
+                links.lock().unwrap().insert(
+                    addr.clone(),
+                    LinkHandle {
+                        client: client.clone(),
+                        counters: task_counters.clone(),
+                    },
+                );
+                let result = tokio::select! {
+                    _ = task_cancel.cancelled() => {
+                        links.lock().unwrap().remove(&addr);
+                        routing.lock().unwrap().forget_neighbor(local_key, &addr);
+                        return;
+                    }
+                    res = client.run_mesh_client(
+                        addr.clone(),
+                        packet_forwarder_handler.clone(),
+                        task_counters.clone(),
+                        routing.clone(),
+                        links.clone(),
+                        local_key,
+                    ) => res,
+                };
+                links.lock().unwrap().remove(&addr);
+                routing.lock().unwrap().forget_neighbor(local_key, &addr);
+                if let Err(e) = result {
+                    tracing::warn!("mesh client for {addr:?} disconnected: {e:?}");
+                }
+
+                if connected_at.elapsed() >= HEALTHY_THRESHOLD {
+                    backoff = INITIAL_BACKOFF;
+                }
+
+                *task_health.lock().unwrap() = LinkHealth::Backoff;
+                tokio::select! {
+                    _ = task_cancel.cancelled() => return,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = next_backoff(backoff);
+            }
+        });
+        self.tasks.insert(
+            addr,
+            MeshClientTask {
+                cancel,
+                handle,
+                health,
+                counters,
+            },
+        );
+    }
+
+    /// Reports the current health of every running mesh link.
+    pub(crate) fn mesh_state(&self) -> Vec<(MeshTarget, LinkHealth)> {
+        self.tasks
+            .iter()
+            .map(|(addr, task)| (addr.clone(), *task.health.lock().unwrap()))
+            .collect()
+    }
+
+    /// Reports a snapshot of the traffic counters for every running mesh
+    /// link.
+    pub(crate) fn stats(&self) -> Vec<MeshLinkStats> {
+        self.tasks
+            .iter()
+            .map(|(addr, task)| MeshLinkStats {
+                target: addr.clone(),
+                bytes_sent: task.counters.bytes_sent.load(Ordering::Relaxed),
+                packets_sent: task.counters.packets_sent.load(Ordering::Relaxed),
+                bytes_received: task.counters.bytes_received.load(Ordering::Relaxed),
+                packets_received: task.counters.packets_received.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Records the node key of the neighboring server reached through
+    /// `addr`, once it is learned (e.g. after the mesh client for `addr`
+    /// connects). Used to build this server's own link-state advertisement.
+    pub(crate) fn record_neighbor_key(&mut self, addr: MeshTarget, key: PublicKey) {
+        self.routing.lock().unwrap().record_neighbor_key(addr, key);
+    }
+
+    /// Builds this server's current link-state advertisement, to be flooded
+    /// across every directly meshed link. Bumps the sequence number, so
+    /// receivers can tell it apart from (and discard anything older than)
+    /// the last one we sent.
+    pub(crate) fn local_advertisement(&mut self, local_key: PublicKey) -> LinkStateAdvertisement {
+        self.routing.lock().unwrap().local_advertisement(local_key)
+    }
+
+    /// Ingests a link-state advertisement received over a mesh link and
+    /// recomputes the forwarding table. Stale advertisements (same or older
+    /// sequence number than the last one seen from that origin) are
+    /// discarded, which also suppresses re-flooded duplicates.
+    pub(crate) fn receive_advertisement(
+        &mut self,
+        local_key: PublicKey,
+        advertisement: LinkStateAdvertisement,
+    ) {
+        self.routing
+            .lock()
+            .unwrap()
+            .receive_advertisement(local_key, advertisement);
+    }
+
+    /// The neighbor target a packet addressed to `dest` should be forwarded
+    /// to, if a route is currently known and `hops` has not yet reached
+    /// [`MAX_FORWARD_HOPS`].
+    ///
+    /// Reads the same shared routing state that the packet-forwarding path
+    /// in `Client::run_mesh_client` consults (through
+    /// `RoutingState::next_hop`) when a received packet's destination is
+    /// not one of this server's local clients.
+    pub(crate) fn next_hop(&self, dest: &PublicKey, hops: u8) -> Option<MeshTarget> {
+        self.routing.lock().unwrap().next_hop(dest, hops)
+    }
+
+    /// Forgets the neighbor reached through `addr`, once its mesh link is
+    /// torn down, so the forwarding table stops treating it as a valid
+    /// route to anything.
+    fn forget_neighbor(&self, addr: &MeshTarget) {
+        self.routing
+            .lock()
+            .unwrap()
+            .forget_neighbor(self.server_key.public_key(), addr);
+    }
+
+    pub(crate) async fn shutdown(mut self) {
+        self.cancel.cancel();
+        for (_, task) in self.tasks.drain() {
+            task.cancel.cancel();
+            task.handle.abort();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// The different ways to express the mesh network you want to join.
+pub enum MeshAddrs {
+    /// Supply a `DerpMap` of all the derp servers you want to mesh with.
+    DerpMap(DerpMap),
+    /// Supply a list of `Url`s of all the derp server you want to mesh with.
+    Addrs(Vec<Url>),
+    /// Mesh with a bounded random sample of `candidates`, of at most
+    /// `view_size` peers, rather than all of them. Use this for large
+    /// meshes where fully meshing every candidate would be O(n²).
+    Sampled {
+        /// All the derp servers that are eligible to be meshed with.
+        candidates: Vec<Url>,
+        /// The maximum number of candidates to actually mesh with.
+        view_size: usize,
+    },
+    /// Supply a list of `MeshTarget`s directly, each either an http(s) `Url`
+    /// or a path to a Unix domain socket. Use this to mesh with co-located
+    /// relays over a `UnixStream`, cutting TCP/TLS overhead between
+    /// processes sharing a host.
+    Targets(Vec<MeshTarget>),
+}
+
+/// An address a mesh client can dial: either an http(s) `Url` reached over
+/// TCP/TLS, or a filesystem path to a Unix domain socket, for mesh links
+/// between relays running on the same host. Serializable so it can appear
+/// inside a `DerpMap`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MeshTarget {
+    /// Dial an http(s) derp server over TCP/TLS.
+    Url(Url),
+    /// Dial a derp server listening on a Unix domain socket at this path.
+    Socket(PathBuf),
+}
+
+impl MeshAddrs {
+    /// Resolves this `MeshAddrs` into the flat list of client urls it
+    /// currently represents.
+    ///
+    /// For `MeshAddrs::Sampled` this returns the full candidate set, not a
+    /// sampled view; `MeshClients::resolve_addrs` handles sampling that
+    /// variant via its `BasaltView`, since the view has state that must
+    /// persist across calls. Not meaningful for `MeshAddrs::Targets`, which
+    /// `MeshClients::resolve_addrs` handles directly instead.
+    fn addrs(&self) -> Vec<Url> {
+        match self {
             MeshAddrs::Addrs(urls) => urls.to_owned(),
             MeshAddrs::DerpMap(derp_map) => {
                 let mut urls = Vec::new();
@@ -57,36 +577,188 @@ impl MeshClients {
                 }
                 urls
             }
-        };
-        for addr in addrs {
-            let client = ClientBuilder::new()
-                .mesh_key(Some(self.mesh_key))
-                .server_url(addr)
-                .build(self.server_key.clone())
-                .expect("will only fail if no `server_url` is present");
-
-            let packet_forwarder_handler = self.packet_fwd.clone();
-            self.tasks.spawn(async move {
-                if let Err(e) = client.run_mesh_client(packet_forwarder_handler).await {
-                    tracing::warn!("{e:?}");
-                }
-            });
+            MeshAddrs::Sampled { candidates, .. } => candidates.to_owned(),
+            MeshAddrs::Targets(_) => Vec::new(),
         }
     }
+}
 
-    pub(crate) async fn shutdown(mut self) {
-        self.cancel.cancel();
-        self.tasks.shutdown().await
+/// A single Basalt bucket: retains the candidate peer that minimizes
+/// `rank(seed, peer)` for this bucket's seed.
+#[derive(Debug, Clone)]
+struct Bucket {
+    seed: [u8; 32],
+    winner: Option<Url>,
+}
+
+/// A Basalt-style bounded random view over a set of candidate mesh peers.
+///
+/// The view is made up of `K` independent buckets, each with its own random
+/// 256-bit seed. For every candidate peer, bucket `i` computes
+/// `rank = hash(seed_i, peer)` and keeps only the candidate that minimizes
+/// it, so the view never grows past `K` members no matter how many
+/// candidates are on offer. Each bucket's winner depends only on its own
+/// seed, so picking one candidate doesn't bias the others, and an attacker
+/// would need to control the minimum-rank candidate under a specific seed to
+/// steer a given bucket at all.
+#[derive(Debug)]
+struct BasaltView {
+    buckets: Vec<Bucket>,
+}
+
+impl BasaltView {
+    fn new(view_size: usize) -> Self {
+        Self {
+            buckets: (0..view_size)
+                .map(|_| Bucket {
+                    seed: random_seed(),
+                    winner: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Recomputes each bucket's winner against the current `candidates`.
+    fn recompute(&mut self, candidates: &[Url]) {
+        for bucket in &mut self.buckets {
+            bucket.winner = candidates
+                .iter()
+                .min_by_key(|candidate| rank(&bucket.seed, candidate))
+                .cloned();
+        }
+    }
+
+    /// Rotates a `fraction` of the bucket seeds, forcing churn in the view
+    /// the next time it is recomputed.
+    fn rotate_seeds(&mut self, fraction: f64) {
+        let rotate = ((self.buckets.len() as f64) * fraction).ceil() as usize;
+        for bucket in self.buckets.iter_mut().take(rotate) {
+            bucket.seed = random_seed();
+        }
     }
+
+    /// The current view: the union of all bucket winners.
+    fn view(&self) -> Vec<Url> {
+        let mut view: Vec<Url> = Vec::new();
+        for winner in self.buckets.iter().filter_map(|bucket| bucket.winner.as_ref()) {
+            if !view.contains(winner) {
+                view.push(winner.clone());
+            }
+        }
+        view
+    }
+}
+
+/// Ranks `peer` for the bucket owning `seed`: a smaller rank wins the
+/// bucket.
+fn rank(seed: &[u8; 32], peer: &Url) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(seed);
+    hasher.update(peer.as_str().as_bytes());
+    *hasher.finalize().as_bytes()
 }
 
+fn random_seed() -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    seed
+}
+
+/// A link-state advertisement: the neighbor set a single mesh server
+/// currently sees, flooded across mesh links so every server can compute
+/// routes to node keys it is not directly meshed with.
 #[derive(Debug, Clone)]
-/// The different ways to express the mesh network you want to join.
-pub enum MeshAddrs {
-    /// Supply a `DerpMap` of all the derp servers you want to mesh with.
-    DerpMap(DerpMap),
-    /// Supply a list of `Url`s of all the derp server you want to mesh with.
-    Addrs(Vec<Url>),
+pub(crate) struct LinkStateAdvertisement {
+    /// The server that originated this advertisement.
+    pub(crate) origin: PublicKey,
+    /// The servers `origin` is currently directly meshed with.
+    pub(crate) neighbors: Vec<PublicKey>,
+    /// Monotonically increasing per-origin sequence number, used to discard
+    /// stale or replayed advertisements.
+    pub(crate) seq: u64,
+}
+
+/// Routes packets to node keys that are not handled by any locally
+/// connected client, over the partial mesh of directly-meshed servers.
+///
+/// Directly-meshed servers are neighbors in a link-state graph. Every
+/// server floods a `LinkStateAdvertisement` of its own neighbor set;
+/// receivers run a breadth-first shortest-path computation over the
+/// resulting graph to pick, for every known remote node key, which neighbor
+/// is the next hop towards it.
+#[derive(Debug, Default)]
+struct ForwardingTable {
+    /// Latest advertisement seen from each origin, keyed by origin node key.
+    advertisements: HashMap<PublicKey, LinkStateAdvertisement>,
+    /// For each remote node key we know how to reach, the neighbor target
+    /// that is the next hop towards it.
+    next_hop: HashMap<PublicKey, MeshTarget>,
+}
+
+impl ForwardingTable {
+    /// Ingests an advertisement, discarding it if it is stale (an old or
+    /// replayed sequence number from the same origin).
+    fn ingest(&mut self, advertisement: LinkStateAdvertisement) {
+        if let Some(existing) = self.advertisements.get(&advertisement.origin) {
+            if advertisement.seq <= existing.seq {
+                return;
+            }
+        }
+        self.advertisements
+            .insert(advertisement.origin, advertisement);
+    }
+
+    /// Forgets the last-seen advertisement from `origin`, if any, so a route
+    /// computed from it doesn't survive a `recompute` once `origin` is no
+    /// longer reachable as a direct neighbor.
+    fn forget(&mut self, origin: &PublicKey) {
+        self.advertisements.remove(origin);
+    }
+
+    /// Recomputes `next_hop` via breadth-first search from `local`, over the
+    /// graph formed by `neighbors` (this server's directly-meshed targets,
+    /// keyed by the neighbor server's node key) plus every known
+    /// advertisement.
+    fn recompute(&mut self, local: PublicKey, neighbors: &HashMap<PublicKey, MeshTarget>) {
+        let mut frontier: VecDeque<PublicKey> = VecDeque::new();
+        let mut first_hop: HashMap<PublicKey, MeshTarget> = HashMap::new();
+        let mut visited: HashSet<PublicKey> = HashSet::new();
+        visited.insert(local);
+
+        for (neighbor_key, neighbor_addr) in neighbors {
+            if visited.insert(*neighbor_key) {
+                first_hop.insert(*neighbor_key, neighbor_addr.clone());
+                frontier.push_back(*neighbor_key);
+            }
+        }
+
+        while let Some(node) = frontier.pop_front() {
+            let Some(advertisement) = self.advertisements.get(&node) else {
+                continue;
+            };
+            let hop = first_hop.get(&node).cloned();
+            for next in &advertisement.neighbors {
+                if visited.insert(*next) {
+                    if let Some(hop) = hop.clone() {
+                        first_hop.insert(*next, hop);
+                    }
+                    frontier.push_back(*next);
+                }
+            }
+        }
+
+        self.next_hop = first_hop;
+    }
+
+    /// The neighbor target a packet for `dest` should be forwarded to, if
+    /// any route is currently known.
+    fn next_hop(&self, dest: &PublicKey) -> Option<&MeshTarget> {
+        self.next_hop.get(dest)
+    }
 }
 
 #[cfg(test)]
@@ -97,6 +769,104 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_next_backoff_doubles_and_caps() {
+        let mut backoff = INITIAL_BACKOFF;
+        backoff = next_backoff(backoff);
+        assert_eq!(backoff, INITIAL_BACKOFF * 2);
+        for _ in 0..20 {
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(backoff, MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_basalt_view_bounded_by_view_size() {
+        let candidates: Vec<Url> = (0..50)
+            .map(|i| format!("http://peer-{i}.example/derp").parse().unwrap())
+            .collect();
+
+        let mut view = BasaltView::new(5);
+        view.recompute(&candidates);
+        assert!(view.len() <= 5);
+        assert!(view.view().len() <= 5);
+
+        // fewer candidates than buckets: the view can't exceed what's on offer.
+        let mut small_view = BasaltView::new(5);
+        small_view.recompute(&candidates[..2]);
+        assert!(small_view.view().len() <= 2);
+    }
+
+    #[test]
+    fn test_basalt_view_rotate_seeds_changes_a_fraction() {
+        let mut view = BasaltView::new(10);
+        let original_seeds: Vec<[u8; 32]> = view.buckets.iter().map(|b| b.seed).collect();
+
+        view.rotate_seeds(0.3);
+
+        let changed = view
+            .buckets
+            .iter()
+            .zip(&original_seeds)
+            .filter(|(bucket, original)| bucket.seed != **original)
+            .count();
+        assert_eq!(changed, 3);
+    }
+
+    #[test]
+    fn test_forwarding_table_multi_hop_bfs() {
+        let local = SecretKey::generate().public_key();
+        let n1 = SecretKey::generate().public_key();
+        let n2 = SecretKey::generate().public_key();
+        let n1_addr = MeshTarget::Socket(PathBuf::from("/tmp/n1.sock"));
+
+        let mut table = ForwardingTable::default();
+        // n1 is directly meshed with us, and in turn meshed with n2, which
+        // we are not directly meshed with.
+        table.ingest(LinkStateAdvertisement {
+            origin: n1,
+            neighbors: vec![local, n2],
+            seq: 1,
+        });
+
+        let mut neighbors = HashMap::new();
+        neighbors.insert(n1, n1_addr.clone());
+        table.recompute(local, &neighbors);
+
+        // n1 is reachable directly; n2 only via n1.
+        assert_eq!(table.next_hop(&n1), Some(&n1_addr));
+        assert_eq!(table.next_hop(&n2), Some(&n1_addr));
+    }
+
+    #[test]
+    fn test_forwarding_table_suppresses_stale_advertisements() {
+        let local = SecretKey::generate().public_key();
+        let n1 = SecretKey::generate().public_key();
+        let n2 = SecretKey::generate().public_key();
+        let n1_addr = MeshTarget::Socket(PathBuf::from("/tmp/n1.sock"));
+
+        let mut table = ForwardingTable::default();
+        table.ingest(LinkStateAdvertisement {
+            origin: n1,
+            neighbors: vec![local, n2],
+            seq: 5,
+        });
+
+        // a replayed advertisement with the same (or older) sequence number
+        // must not override the route it already established.
+        table.ingest(LinkStateAdvertisement {
+            origin: n1,
+            neighbors: vec![local],
+            seq: 5,
+        });
+
+        let mut neighbors = HashMap::new();
+        neighbors.insert(n1, n1_addr.clone());
+        table.recompute(local, &neighbors);
+
+        assert_eq!(table.next_hop(&n2), Some(&n1_addr));
+    }
+
     #[tokio::test]
     async fn test_mesh_network() -> Result<()> {
         tracing_subscriber::registry()
@@ -196,4 +966,4 @@ mod tests {
         derp_server_b.shutdown().await;
         Ok(())
     }
-}
\ No newline at end of file
+}